@@ -0,0 +1,108 @@
+//! Persistent hash cache, to avoid re-hashing unchanged files across runs
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::HashType;
+
+/// On-disk format version, bumped whenever `CacheKey` or `HashCache`'s shape changes so that an
+/// incompatible cache file is rejected instead of risking a garbage decode via bincode's
+/// non-self-describing encoding
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Cache key identifying a file's content as it was last hashed
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    /// File size in bytes
+    size: u64,
+    /// Last modification time, in nanoseconds since epoch
+    mtime_nanos: i64,
+    /// Hash algorithm the digest below was computed with
+    algo: HashType,
+}
+
+/// On-disk representation of the hash cache
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashCache {
+    entries: HashMap<PathBuf, (CacheKey, Vec<u8>)>,
+}
+
+impl HashCache {
+    /// Default cache file path, under the user cache dir
+    pub fn default_path() -> anyhow::Result<PathBuf> {
+        let cache_dir = dirs::cache_dir()
+            .ok_or_else(|| anyhow::anyhow!("Unable to determine user cache directory"))?;
+        Ok(cache_dir.join("bdf").join("hash_cache.bin"))
+    }
+
+    /// Load the cache from `path`, returning an empty cache if it does not exist yet
+    pub fn load_cache_from_file(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let format_version: u32 = bincode::deserialize_from(&mut reader)?;
+        anyhow::ensure!(
+            format_version == CACHE_FORMAT_VERSION,
+            "Cache file has format version {} but {} is expected",
+            format_version,
+            CACHE_FORMAT_VERSION
+        );
+        let cache = bincode::deserialize_from(reader)?;
+        Ok(cache)
+    }
+
+    /// Save the cache to `path`, creating parent directories as needed
+    pub fn save_cache_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut writer, &CACHE_FORMAT_VERSION)?;
+        bincode::serialize_into(writer, self)?;
+        Ok(())
+    }
+
+    /// Look up a cached digest for `path`, if its size, mtime and hash algorithm still match
+    pub fn get(&self, path: &Path, size: u64, mtime_nanos: i64, algo: HashType) -> Option<&[u8]> {
+        let (key, digest) = self.entries.get(path)?;
+        if (key.size == size) && (key.mtime_nanos == mtime_nanos) && (key.algo == algo) {
+            Some(digest)
+        } else {
+            None
+        }
+    }
+
+    /// Insert or update the cached digest for `path`
+    pub fn insert(
+        &mut self,
+        path: PathBuf,
+        size: u64,
+        mtime_nanos: i64,
+        algo: HashType,
+        digest: Vec<u8>,
+    ) {
+        self.entries.insert(
+            path,
+            (
+                CacheKey {
+                    size,
+                    mtime_nanos,
+                    algo,
+                },
+                digest,
+            ),
+        );
+    }
+
+    /// Remove entries whose path no longer exists on disk
+    pub fn prune_stale(&mut self) {
+        self.entries.retain(|path, _| path.exists());
+    }
+}
@@ -1,30 +1,124 @@
 //! Btrfs Duplicate Finder
 
+mod cache;
+
 use std::{
     cmp::max,
-    collections::hash_map::{Entry, HashMap},
+    collections::{
+        hash_map::{Entry, HashMap},
+        HashSet,
+    },
     ffi::OsStr,
     fmt,
-    fs::File,
+    fs::{File, Metadata, OpenOptions},
     io::{self, BufRead, BufReader, Read},
-    os::unix::ffi::OsStrExt,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     thread,
     time::Duration,
 };
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use multimap::MultiMap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use xxhash_rust::xxh3;
 
+use crate::cache::HashCache;
+
+/// Selectable file hashing algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+pub enum HashType {
+    /// XXH3-64, fast non cryptographic hash (default)
+    Xxh3,
+    /// CRC32, fast but collision prone
+    Crc32,
+    /// Blake3, cryptographic hash, strong enough to skip byte-for-byte verification
+    Blake3,
+}
+
+impl HashType {
+    /// Build a fresh hasher for this algorithm
+    fn hasher(self) -> Box<dyn DigestHasher> {
+        match self {
+            Self::Xxh3 => Box::new(xxh3::Xxh3::new()),
+            Self::Crc32 => Box::new(crc32fast::Hasher::new()),
+            Self::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+/// Common interface over the selectable hash algorithms
+trait DigestHasher {
+    /// Reset internal state, to reuse the hasher for a new file
+    fn reset(&mut self);
+    /// Feed a chunk of data into the hasher
+    fn update(&mut self, data: &[u8]);
+    /// Finalize and return the digest
+    fn digest(&mut self) -> Vec<u8>;
+}
+
+impl DigestHasher for xxh3::Xxh3 {
+    fn reset(&mut self) {
+        xxh3::Xxh3::reset(self);
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        xxh3::Xxh3::update(self, data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        xxh3::Xxh3::digest(self).to_le_bytes().to_vec()
+    }
+}
+
+impl DigestHasher for crc32fast::Hasher {
+    fn reset(&mut self) {
+        *self = crc32fast::Hasher::new();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        crc32fast::Hasher::update(self, data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.clone().finalize().to_le_bytes().to_vec()
+    }
+}
+
+impl DigestHasher for blake3::Hasher {
+    fn reset(&mut self) {
+        blake3::Hasher::reset(self);
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+// FICLONE ioctl (see linux/fs.h), takes the source fd as its argument
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Return a file's modification time as nanoseconds since epoch
+fn mtime_nanos(metadata: &Metadata) -> i64 {
+    metadata.mtime() * 1_000_000_000 + i64::from(metadata.mtime_nsec())
+}
+
 /// File read chunk size, in bytes
 const READ_BUFFER_SIZE: usize = 256 * 1024;
 
+/// Number of leading bytes hashed during the cheap prefix hashing stage
+const PREFIX_SIZE: usize = 16 * 1024;
+
 /// Convenience type for a pair of crossbeam channel ends
 type CrossbeamChannel<T> = (crossbeam_channel::Sender<T>, crossbeam_channel::Receiver<T>);
 
@@ -41,14 +135,35 @@ pub struct CommandLineOpts {
     /// Minimum file size in bytes to consider
     #[structopt(short, long)]
     pub min_size: Option<u64>,
+
+    /// Perform the reflink (Btrfs clone) of duplicate candidates instead of just printing them
+    #[structopt(long)]
+    pub reflink: bool,
+
+    /// Disable the persistent hash cache
+    #[structopt(long)]
+    pub no_cache: bool,
+
+    /// Path to the persistent hash cache file (defaults to a file under the user cache dir)
+    #[structopt(long)]
+    pub cache_path: Option<PathBuf>,
+
+    /// Hash algorithm used to compare file content
+    #[structopt(long, value_enum, default_value_t = HashType::Xxh3)]
+    pub hash_algo: HashType,
+
+    /// Trust the hash algorithm and skip the final byte-for-byte content verification
+    /// (only safe with a cryptographic hash like Blake3)
+    #[structopt(long)]
+    pub trust_hash: bool,
 }
 
-/// Compute XXH3-64 non cryptographic hash
-fn compute_xxh(
-    hasher: &mut xxh3::Xxh3,
+/// Compute a digest of a whole file
+fn compute_hash(
+    hasher: &mut dyn DigestHasher,
     reader: &mut BufReader<File>,
     buffer: &mut [u8],
-) -> Result<u64, io::Error> {
+) -> Result<Vec<u8>, io::Error> {
     hasher.reset();
     loop {
         let rd_count = reader.read(buffer)?;
@@ -60,6 +175,25 @@ fn compute_xxh(
     Ok(hasher.digest())
 }
 
+/// Compute a digest over only the first `PREFIX_SIZE` bytes of the file
+fn compute_hash_prefix(
+    hasher: &mut dyn DigestHasher,
+    reader: &mut BufReader<File>,
+    buffer: &mut [u8],
+) -> Result<Vec<u8>, io::Error> {
+    hasher.reset();
+    let mut remaining = PREFIX_SIZE;
+    while remaining > 0 {
+        let rd_count = reader.read(&mut buffer[..remaining.min(buffer.len())])?;
+        if rd_count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..rd_count]);
+        remaining -= rd_count;
+    }
+    Ok(hasher.digest())
+}
+
 /// Processing progress counters
 struct ProgressCounters {
     /// Number of files that were targeted for analysis
@@ -72,6 +206,8 @@ struct ProgressCounters {
     reflinked_count: AtomicUsize,
     /// Number of duplicate files, candidates for reflinking
     duplicate_candidate_count: AtomicUsize,
+    /// Number of duplicate files reflinked by this run
+    reflinked_now_count: AtomicUsize,
 }
 
 impl ProgressCounters {
@@ -83,6 +219,7 @@ impl ProgressCounters {
             hash_collision_count: AtomicUsize::new(0),
             reflinked_count: AtomicUsize::new(0),
             duplicate_candidate_count: AtomicUsize::new(0),
+            reflinked_now_count: AtomicUsize::new(0),
         }
     }
 }
@@ -91,12 +228,13 @@ impl fmt::Display for ProgressCounters {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} files, {} hashes, {} hash collisions, {} already reflinked, {} duplicates",
+            "{} files, {} hashes, {} hash collisions, {} already reflinked, {} duplicates, {} reflinked now",
             self.file_count.load(Ordering::Relaxed),
             self.hash_count.load(Ordering::Relaxed),
             self.hash_collision_count.load(Ordering::Relaxed),
             self.reflinked_count.load(Ordering::Relaxed),
             self.duplicate_candidate_count.load(Ordering::Relaxed),
+            self.reflinked_now_count.load(Ordering::Relaxed),
         )
     }
 }
@@ -140,6 +278,91 @@ fn same_extents(first: &Path, second: &Path) -> Result<bool, io::Error> {
     Ok(true)
 }
 
+/// Reflink `other` to share `first`'s extents, preserving `other`'s mode and mtime
+///
+/// `*_expected_size` and `*_expected_mtime` are each file's size and mtime (nanoseconds since epoch) as they were when that
+/// file was hashed. Returns `false` (without error) if the clone ioctl is not supported between
+/// these two files (e.g. different subvolumes), or if either `first` or `other` was modified since
+/// it was hashed, to avoid cloning from, or clobbering, data that changed during the scan.
+fn reflink(
+    first: &Path,
+    first_expected_size: u64,
+    first_expected_mtime: i64,
+    other: &Path,
+    other_expected_size: u64,
+    other_expected_mtime: i64,
+) -> Result<bool, io::Error> {
+    let first_metadata = first.metadata()?;
+    if (first_metadata.len() != first_expected_size)
+        || (mtime_nanos(&first_metadata) != first_expected_mtime)
+    {
+        log::warn!(
+            "{:?} was modified since it was hashed, skipping reflink to avoid data loss",
+            first
+        );
+        return Ok(false);
+    }
+    let other_metadata = other.metadata()?;
+    if (other_metadata.len() != other_expected_size)
+        || (mtime_nanos(&other_metadata) != other_expected_mtime)
+    {
+        log::warn!(
+            "{:?} was modified since it was hashed, skipping reflink to avoid data loss",
+            other
+        );
+        return Ok(false);
+    }
+
+    let src_file = File::open(first)?;
+    let dst_file = OpenOptions::new().write(true).open(other)?;
+
+    let ioctl_res = unsafe { ficlone(dst_file.as_raw_fd(), src_file.as_raw_fd() as _) };
+    match ioctl_res {
+        Ok(_) => {}
+        Err(nix::errno::Errno::EOPNOTSUPP) | Err(nix::errno::Errno::EXDEV) => {
+            log::warn!(
+                "Reflink of {:?} to {:?} is not supported (different subvolumes or filesystems?)",
+                first,
+                other
+            );
+            return Ok(false);
+        }
+        Err(e) => return Err(io::Error::from(e)),
+    }
+
+    // Restore mode & mtime clobbered by the clone
+    let permissions = other_metadata.permissions();
+    std::fs::set_permissions(other, permissions)?;
+    let mtime = filetime::FileTime::from_unix_time(
+        other_expected_mtime.div_euclid(1_000_000_000),
+        other_expected_mtime.rem_euclid(1_000_000_000) as u32,
+    );
+    filetime::set_file_mtime(other, mtime)?;
+
+    Ok(true)
+}
+
+/// Given a group of byte-identical files, pick the one that already shares extents with the most
+/// others as the reflink master, so the fewest clone operations are needed to deduplicate the
+/// group. Returns the master's index and, for every file in `identical`, whether it already
+/// shares extents with the master.
+fn select_reflink_master(identical: &[(PathBuf, i64)]) -> Result<(usize, Vec<bool>), io::Error> {
+    let n = identical.len();
+    let mut shared_with = vec![vec![false; n]; n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if same_extents(&identical[i].0, &identical[j].0)? {
+                shared_with[i][j] = true;
+                shared_with[j][i] = true;
+            }
+        }
+    }
+    let master_idx = (0..n)
+        .max_by_key(|&i| shared_with[i].iter().filter(|shares| **shares).count())
+        .unwrap();
+    Ok((master_idx, std::mem::take(&mut shared_with[master_idx])))
+}
+
 /// Return true if path is on a Btrfs filesystem
 fn is_on_btrfs(path: &Path) -> nix::Result<bool> {
     let statfs = nix::sys::statfs::statfs(path)?;
@@ -162,37 +385,66 @@ fn main() -> anyhow::Result<()> {
             input_dir
         );
     }
+    if cl_opts.trust_hash && (cl_opts.hash_algo != HashType::Blake3) {
+        log::warn!(
+            "--trust-hash with {:?} risks data loss on hash collisions, Blake3 is recommended",
+            cl_opts.hash_algo
+        );
+    }
 
     // Get usable core count
     let cpu_count = thread::available_parallelism()?.get();
 
-    // Channels
-    let (to_hashed_tx, to_hashed_rx): CrossbeamChannel<(PathBuf, u64)> =
+    // Load the persistent hash cache
+    let cache_path = match cl_opts.cache_path.as_ref() {
+        Some(cache_path) => cache_path.to_owned(),
+        None => HashCache::default_path()?,
+    };
+    let mut hash_cache = if cl_opts.no_cache {
+        HashCache::default()
+    } else {
+        HashCache::load_cache_from_file(&cache_path)
+            .context("Failed to load hash cache")
+            .unwrap_or_else(|e| {
+                log::warn!("{}", e);
+                HashCache::default()
+            })
+    };
+
+    let hash_algo = cl_opts.hash_algo;
+
+    // Channels for the prefix hashing stage
+    let (to_prefix_tx, to_prefix_rx): CrossbeamChannel<(PathBuf, u64, i64)> =
         crossbeam_channel::unbounded();
-    let (hashed_tx, hashed_rx): CrossbeamChannel<(PathBuf, u64, u64)> =
+    let (prefix_hashed_tx, prefix_hashed_rx): CrossbeamChannel<(PathBuf, u64, i64, Vec<u8>)> =
         crossbeam_channel::unbounded();
 
-    // File hash map
-    let mut files: MultiMap<(u64, u64), PathBuf> = MultiMap::new();
+    // File hash map, keyed by (size, hash) and storing each file's mtime as it was when hashed,
+    // so later reflinking can detect files modified during the (possibly long) scan
+    let mut files: MultiMap<(u64, Vec<u8>), (PathBuf, i64)> = MultiMap::new();
+    // Hashes served from the cache, not re-computed this run
+    let mut cached_hashes: Vec<(PathBuf, u64, i64, Vec<u8>)> = Vec::new();
 
     // Progress
     let progress = indicatif::ProgressBar::new_spinner();
     progress.enable_steady_tick(Duration::from_millis(300));
     let progress_counters = Arc::new(ProgressCounters::new());
 
-    crossbeam_utils::thread::scope(|scope| -> anyhow::Result<()> {
+    // Stage 1: cheap prefix hash of every size-colliding file, to weed out files that will never
+    // match without reading them in full
+    let prefix_groups = crossbeam_utils::thread::scope(|scope| -> anyhow::Result<_> {
         // Worker threads
         for _ in 0..max(cpu_count - 1, 1) {
             // Per thread clones
-            let to_hashed_rx = to_hashed_rx.clone();
-            let hashed_tx = hashed_tx.clone();
+            let to_prefix_rx = to_prefix_rx.clone();
+            let prefix_hashed_tx = prefix_hashed_tx.clone();
             let progress = progress.clone();
             let progress_counters = Arc::clone(&progress_counters);
 
             scope.spawn(move |_| -> anyhow::Result<()> {
-                let mut hasher = xxh3::Xxh3::new();
+                let mut hasher = hash_algo.hasher();
                 let mut buffer = [0; READ_BUFFER_SIZE];
-                while let Ok((path, file_size)) = to_hashed_rx.recv() {
+                while let Ok((path, file_size, mtime)) = to_prefix_rx.recv() {
                     let file = match File::open(&path) {
                         Ok(file) => file,
                         Err(e) => {
@@ -202,23 +454,36 @@ fn main() -> anyhow::Result<()> {
                     };
 
                     let mut reader = BufReader::new(file);
-                    let hash = compute_xxh(&mut hasher, &mut reader, &mut buffer)?;
+                    let prefix_hash = compute_hash_prefix(hasher.as_mut(), &mut reader, &mut buffer)?;
 
-                    log::debug!("{:?} {:016x}", path, hash);
-                    progress_counters.hash_count.fetch_add(1, Ordering::AcqRel);
-                    progress.set_message(format!("{progress_counters}"));
+                    log::debug!("{:?} prefix {}", path, hex::encode(&prefix_hash));
 
-                    hashed_tx.send((path, file_size, hash))?;
+                    prefix_hashed_tx.send((path, file_size, mtime, prefix_hash))?;
                 }
 
                 Ok(())
             });
         }
-        drop(to_hashed_rx);
-        drop(hashed_tx);
+        drop(to_prefix_rx);
+        drop(prefix_hashed_tx);
+
+        // Look up the cache for a path, sending it to the prefix hashing channel on a miss. Takes
+        // the file's `Metadata` as already fetched by the caller, to avoid a second stat syscall.
+        let mut enqueue = |path: PathBuf, file_size: u64, metadata: &Metadata| -> anyhow::Result<()> {
+            let mtime = mtime_nanos(metadata);
+            if let Some(hash) = hash_cache.get(&path, file_size, mtime, hash_algo) {
+                log::debug!("{:?} {} (cached)", path, hex::encode(hash));
+                progress_counters.hash_count.fetch_add(1, Ordering::AcqRel);
+                progress.set_message(format!("{progress_counters}"));
+                cached_hashes.push((path, file_size, mtime, hash.to_vec()));
+            } else {
+                to_prefix_tx.send((path, file_size, mtime))?;
+            }
+            Ok(())
+        };
 
         // Iterate over files
-        let mut entry_map: HashMap<u64, Option<walkdir::DirEntry>> = HashMap::new();
+        let mut entry_map: HashMap<u64, Option<(walkdir::DirEntry, Metadata)>> = HashMap::new();
         if let Some(input_dir) = cl_opts.dir {
             for entry in walkdir::WalkDir::new(input_dir)
                 .same_file_system(true)
@@ -234,7 +499,8 @@ fn main() -> anyhow::Result<()> {
                 if !entry.file_type().is_file() {
                     continue;
                 }
-                let file_size = entry.metadata()?.len();
+                let metadata = entry.metadata()?;
+                let file_size = metadata.len();
                 if file_size == 0 {
                     // Don't bother for empty files
                     continue;
@@ -253,21 +519,21 @@ fn main() -> anyhow::Result<()> {
                 // This allows saving some hash computations for the common case
                 match entry_map.entry(file_size) {
                     Entry::Vacant(e) => {
-                        // First file for this size, keep entry and move along
-                        e.insert(Some(entry));
+                        // First file for this size, keep entry and metadata and move along
+                        e.insert(Some((entry, metadata)));
                     }
                     Entry::Occupied(e) => {
                         match e.get() {
                             Some(_) => {
                                 // Second file for this size, send this one and the previous to the channel, and set map
                                 // so the next ones will be sent immediately
-                                let prev_entry = e.into_mut().take().unwrap();
-                                to_hashed_tx.send((prev_entry.path().to_path_buf(), file_size))?;
-                                to_hashed_tx.send((path.to_path_buf(), file_size))?;
+                                let (prev_entry, prev_metadata) = e.into_mut().take().unwrap();
+                                enqueue(prev_entry.path().to_path_buf(), file_size, &prev_metadata)?;
+                                enqueue(path.to_path_buf(), file_size, &metadata)?;
                             }
                             None => {
                                 // Not the first file not second for this size, send it to channel immediately
-                                to_hashed_tx.send((path.to_path_buf(), file_size))?;
+                                enqueue(path.to_path_buf(), file_size, &metadata)?;
                             }
                         }
                     }
@@ -307,7 +573,8 @@ fn main() -> anyhow::Result<()> {
                     );
                     first = false;
                 }
-                let file_size = entry.metadata()?.len();
+                let metadata = entry.metadata()?;
+                let file_size = metadata.len();
                 if file_size == 0 {
                     // Don't bother for empty files
                     continue;
@@ -321,19 +588,106 @@ fn main() -> anyhow::Result<()> {
                 progress_counters.file_count.fetch_add(1, Ordering::AcqRel);
                 progress.set_message(format!("{progress_counters}"));
 
-                to_hashed_tx.send((path.to_path_buf(), file_size))?;
+                enqueue(path.to_path_buf(), file_size, &metadata)?;
+            }
+        }
+        drop(enqueue);
+        drop(to_prefix_tx);
+
+        // Group by (size, prefix hash) and drop singletons: a file whose prefix matches no other
+        // file of the same size can't have a duplicate, so it's not worth a full read. Files of a
+        // size already present in the cache are the exception: we have no prefix hash for the
+        // cached file to group against (cache hits skip prefix hashing entirely), so a singleton
+        // here may still turn out to be a duplicate of a cached file once fully hashed.
+        let cached_sizes: HashSet<u64> = cached_hashes.iter().map(|(_, size, ..)| *size).collect();
+        let mut prefix_groups: MultiMap<(u64, Vec<u8>), (PathBuf, i64)> = MultiMap::new();
+        for (filepath, file_size, mtime, prefix_hash) in prefix_hashed_rx.iter() {
+            prefix_groups.insert((file_size, prefix_hash), (filepath, mtime));
+        }
+        for key in prefix_groups
+            .keys()
+            .filter(|k| !prefix_groups.is_vec(k) && !cached_sizes.contains(&k.0))
+            .map(|k| k.to_owned())
+            .collect::<Vec<_>>()
+        {
+            prefix_groups.remove(&key);
+        }
+
+        Ok(prefix_groups)
+    })
+    .map_err(|e| anyhow::anyhow!("Worker thread error: {:?}", e))??;
+
+    // Stage 2: full hash of the prefix hashing survivors
+    let (to_hashed_tx, to_hashed_rx): CrossbeamChannel<(PathBuf, u64, i64)> =
+        crossbeam_channel::unbounded();
+    let (hashed_tx, hashed_rx): CrossbeamChannel<(PathBuf, u64, i64, Vec<u8>)> =
+        crossbeam_channel::unbounded();
+
+    crossbeam_utils::thread::scope(|scope| -> anyhow::Result<()> {
+        // Worker threads
+        for _ in 0..max(cpu_count - 1, 1) {
+            // Per thread clones
+            let to_hashed_rx = to_hashed_rx.clone();
+            let hashed_tx = hashed_tx.clone();
+            let progress = progress.clone();
+            let progress_counters = Arc::clone(&progress_counters);
+
+            scope.spawn(move |_| -> anyhow::Result<()> {
+                let mut hasher = hash_algo.hasher();
+                let mut buffer = [0; READ_BUFFER_SIZE];
+                while let Ok((path, file_size, mtime)) = to_hashed_rx.recv() {
+                    let file = match File::open(&path) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            log::warn!("Error while opening {:?}: {}", path, e);
+                            continue;
+                        }
+                    };
+
+                    let mut reader = BufReader::new(file);
+                    let hash = compute_hash(hasher.as_mut(), &mut reader, &mut buffer)?;
+
+                    log::debug!("{:?} {}", path, hex::encode(&hash));
+                    progress_counters.hash_count.fetch_add(1, Ordering::AcqRel);
+                    progress.set_message(format!("{progress_counters}"));
+
+                    hashed_tx.send((path, file_size, mtime, hash))?;
+                }
+
+                Ok(())
+            });
+        }
+        drop(to_hashed_rx);
+        drop(hashed_tx);
+
+        for ((file_size, _prefix_hash), survivors) in prefix_groups {
+            for (filepath, mtime) in survivors {
+                to_hashed_tx.send((filepath, file_size, mtime))?;
             }
         }
         drop(to_hashed_tx);
 
-        // Fill hashmap
-        for (filepath, file_size, hash) in hashed_rx.iter() {
-            files.insert((file_size, hash), filepath);
+        // Fill hashmap, both with freshly computed hashes and cache hits, and update the cache
+        // with newly computed hashes
+        for (filepath, file_size, mtime, hash) in hashed_rx.iter() {
+            hash_cache.insert(filepath.clone(), file_size, mtime, hash_algo, hash.clone());
+            files.insert((file_size, hash), (filepath, mtime));
+        }
+        for (filepath, file_size, mtime, hash) in cached_hashes.drain(..) {
+            files.insert((file_size, hash), (filepath, mtime));
         }
         Ok(())
     })
     .map_err(|e| anyhow::anyhow!("Worker thread error: {:?}", e))??;
 
+    // Persist the hash cache for future runs
+    if !cl_opts.no_cache {
+        hash_cache.prune_stale();
+        if let Err(e) = hash_cache.save_cache_to_file(&cache_path) {
+            log::warn!("Failed to save hash cache: {}", e);
+        }
+    }
+
     // Remove unique hashes
     for key in files
         .keys()
@@ -344,46 +698,132 @@ fn main() -> anyhow::Result<()> {
         files.remove(&key);
     }
 
-    // Find candidates
-    for ((_file_size, _file_hash), filepaths) in files.iter_all_mut() {
-        let first = filepaths.first().unwrap();
-        for other in filepaths.iter().skip(1) {
-            if !same_content(first, other)? {
-                log::warn!(
-                    "Files {:?} and {:?} have the same size and hash but not the same content",
-                    first,
-                    other
+    // Find candidates, one group per rayon task; stdout writes are serialized through a mutex so
+    // the NUL-delimited pairs of concurrently processed groups don't interleave
+    let stdout_lock = Mutex::new(());
+    let group_summaries = files
+        .iter_all()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|((file_size, _file_hash), filepaths)| -> anyhow::Result<(usize, u64)> {
+            let file_size = *file_size;
+            let (first, first_mtime) = filepaths.first().unwrap();
+
+            // Weed out hash collisions to get the actual set of byte-identical files, unless the
+            // user trusts the hash algorithm enough to skip this byte-for-byte verification
+            let identical: Vec<(PathBuf, i64)> = if cl_opts.trust_hash {
+                filepaths.clone()
+            } else {
+                let mut identical: Vec<(PathBuf, i64)> = vec![(first.to_owned(), *first_mtime)];
+                for (other, other_mtime) in filepaths.iter().skip(1) {
+                    if same_content(first, other)? {
+                        identical.push((other.to_owned(), *other_mtime));
+                    } else {
+                        log::warn!(
+                            "Files {:?} and {:?} have the same size and hash but not the same content",
+                            first,
+                            other
+                        );
+                        progress_counters
+                            .hash_collision_count
+                            .fetch_add(1, Ordering::AcqRel);
+                        progress.set_message(format!("{progress_counters}"));
+                    }
+                }
+                identical
+            };
+            if identical.len() < 2 {
+                return Ok((0, 0));
+            }
+
+            // Pick the master that already shares extents with the most others, to minimize the
+            // number of reflinks needed to fully deduplicate the group
+            let (master_idx, shared_with_master) = select_reflink_master(&identical)?;
+            let (master, master_mtime) = &identical[master_idx];
+            let already_shared_count = shared_with_master
+                .iter()
+                .filter(|shares| **shares)
+                .count();
+
+            let mut group_candidate_count: u64 = 0;
+            for (i, (other, other_mtime)) in identical.iter().enumerate() {
+                if i == master_idx {
+                    continue;
+                }
+
+                if shared_with_master[i] {
+                    log::debug!("Files {:?} and {:?} are already reflinked", master, other);
+                    progress_counters
+                        .reflinked_count
+                        .fetch_add(1, Ordering::AcqRel);
+                    progress.set_message(format!("{progress_counters}"));
+                    continue;
+                }
+
+                log::debug!(
+                    "Files {:?} and {:?} are duplicates",
+                    master.to_str().unwrap(),
+                    other.to_str().unwrap()
                 );
                 progress_counters
-                    .hash_collision_count
+                    .duplicate_candidate_count
                     .fetch_add(1, Ordering::AcqRel);
                 progress.set_message(format!("{progress_counters}"));
-                continue;
+                group_candidate_count += 1;
+
+                if cl_opts.reflink {
+                    match reflink(
+                        master,
+                        file_size,
+                        *master_mtime,
+                        other,
+                        file_size,
+                        *other_mtime,
+                    ) {
+                        Ok(true) => {
+                            progress_counters
+                                .reflinked_now_count
+                                .fetch_add(1, Ordering::AcqRel);
+                            progress.set_message(format!("{progress_counters}"));
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::warn!("Error while reflinking {:?} to {:?}: {}", master, other, e);
+                        }
+                    }
+                } else {
+                    let _guard = stdout_lock.lock().unwrap();
+                    print!("{}\0{}\0", master.to_str().unwrap(), other.to_str().unwrap());
+                }
             }
 
-            if same_extents(first, other)? {
-                log::debug!("Files {:?} and {:?} are already reflinked", first, other);
-                progress_counters
-                    .reflinked_count
-                    .fetch_add(1, Ordering::AcqRel);
-                progress.set_message(format!("{progress_counters}"));
-                continue;
+            if group_candidate_count > 0 {
+                let reclaimable_bytes = file_size * group_candidate_count;
+                log::info!(
+                    "Group of {} identical files ({} bytes each): {} already reflinked to {:?}, {} bytes reclaimable",
+                    identical.len(),
+                    file_size,
+                    already_shared_count,
+                    master,
+                    reclaimable_bytes
+                );
+                Ok((1, reclaimable_bytes))
+            } else {
+                Ok((0, 0))
             }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-            log::debug!(
-                "Files {:?} and {:?} are duplicates",
-                first.to_str().unwrap(),
-                other.to_str().unwrap()
-            );
-            progress_counters
-                .duplicate_candidate_count
-                .fetch_add(1, Ordering::AcqRel);
-            progress.set_message(format!("{progress_counters}"));
-            print!("{}\0{}\0", first.to_str().unwrap(), other.to_str().unwrap());
-        }
-    }
+    let total_group_count: usize = group_summaries.iter().map(|(count, _)| count).sum();
+    let total_reclaimable_bytes: u64 = group_summaries.iter().map(|(_, bytes)| bytes).sum();
 
     progress.finish();
 
+    if total_group_count > 0 {
+        log::info!(
+            "{total_group_count} duplicate group(s) with reclaimable space, {total_reclaimable_bytes} bytes reclaimable by reflinking"
+        );
+    }
+
     Ok(())
 }